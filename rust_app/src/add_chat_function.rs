@@ -4,224 +4,938 @@
 //! It primarily focuses on processing HTTP requests and generating appropriate responses,
 //! leveraging AWS services like DynamoDB and external APIs such as OpenAI.
 use async_openai::{
-    config::OpenAIConfig, types::CreateCompletionRequestArgs, Client as OpenAIClient,
+    config::OpenAIConfig,
+    error::OpenAIError,
+    types::{
+        ChatCompletionRequestAssistantMessageArgs, ChatCompletionRequestSystemMessageArgs,
+        ChatCompletionRequestUserMessageArgs, CreateChatCompletionRequestArgs,
+        CreateChatCompletionStreamResponse, CreateImageRequestArgs, Image, ImageSize,
+    },
+    Client as OpenAIClient,
 };
 use aws_config::BehaviorVersion;
 use aws_sdk_dynamodb::types::AttributeValue;
 use aws_sdk_dynamodb::{Client, Error as DynamoError};
+use futures::stream::BoxStream;
+use futures::StreamExt;
+use lambda_runtime::streaming::{Body, Response as StreamingResponse};
 use lambda_runtime::{run, service_fn, Error, LambdaEvent};
 use serde::{Deserialize, Serialize};
+use std::hash::{Hash, Hasher};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::sync::mpsc;
+use uuid::Uuid;
+
+/// The instructions baked into every itinerary prompt. Bumping this text changes
+/// [`prompt_version`]'s output, which in turn changes every cache key, so a prompt change
+/// naturally invalidates previously cached itineraries instead of silently reusing stale ones.
+const TRAVEL_AGENT_PROMPT: &str = "You are an experienced travel agent that will provide an in-depth itinerary based on relevant online articles. You will provide the itinerary based on the location and duration entered by the user. Include at least 3 activities a day. Do not include any other suggestions or comments before or after the itinerary.";
+
+/// How long a cached itinerary remains valid before it's treated as a cache miss.
+const CACHE_TTL_DAYS: u64 = 7;
+
+/// How long an idle session is kept before its history expires.
+const SESSION_TTL_DAYS: u64 = 1;
+
+/// A short hash of the active `system_prompt`, used as part of the cache key so that changing
+/// the prompt template (whether that's [`TRAVEL_AGENT_PROMPT`] or an operator-supplied override)
+/// invalidates previously cached itineraries rather than serving stale ones generated under a
+/// different set of instructions.
+fn prompt_version(system_prompt: &str) -> u64 {
+    let mut hasher = std::collections::hash_map::DefaultHasher::new();
+    system_prompt.hash(&mut hasher);
+    hasher.finish()
+}
+
+/// Builds the DynamoDB cache key for a given conversation transcript, model, and system prompt.
+///
+/// The key combines the raw `input`, the `model` name, and [`prompt_version`] so that switching
+/// models or editing the system prompt can't silently serve a cached itinerary generated under
+/// different conditions.
+fn cache_key(input: &str, model: &str, system_prompt: &str) -> String {
+    format!("{model}:{:x}:{input}", prompt_version(system_prompt))
+}
+
+/// Strips control and zero-width characters from user-supplied text before it's sent to the
+/// model as a `user` message. The system/user role split already prevents user text from being
+/// interpreted as an instruction override; this additionally guards against characters that
+/// could be used to obscure an injection attempt from logs or review.
+fn sanitize_user_input(input: &str) -> String {
+    input
+        .chars()
+        .filter(|c| !c.is_control() || *c == '\n')
+        .filter(|c| !matches!(c, '\u{200B}'..='\u{200D}' | '\u{2060}' | '\u{FEFF}'))
+        .collect::<String>()
+        .trim()
+        .to_string()
+}
+
+/// Maps a configured `image_size` string (e.g. `1024x1024`) to the `async-openai` `ImageSize`
+/// enum, falling back to `512x512` for anything unrecognized.
+fn parse_image_size(raw: &str) -> ImageSize {
+    match raw {
+        "256x256" => ImageSize::S256x256,
+        "1024x1024" => ImageSize::S1024x1024,
+        _ => ImageSize::S512x512,
+    }
+}
+
+/// Returns the current Unix epoch time in seconds.
+fn now_epoch_secs() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+/// Everything that can go wrong while handling a request, classified so `function_handler` can
+/// map each variant to a distinct HTTP status code instead of returning `200` regardless of what
+/// failed.
+#[derive(Debug, thiserror::Error)]
+enum WonderNavError {
+    /// A DynamoDB read or write failed (network issues, permissions, throttling).
+    #[error("DynamoDB operation failed: {0}")]
+    DynamoQuery(#[from] DynamoError),
+    /// The upstream LLM provider rejected or failed the request for a reason other than rate
+    /// limiting (bad API key, malformed request, provider outage).
+    #[error("upstream LLM request failed: {0}")]
+    OpenAiRequest(String),
+    /// The upstream LLM provider reported that its rate limit was exceeded.
+    #[error("rate limited by the upstream LLM provider")]
+    RateLimited,
+    /// A DynamoDB item was missing an attribute its schema requires, rather than simply not
+    /// existing — distinct from a cache miss, which is `Ok(None)`, not an error.
+    #[error("expected attribute `{0}` missing from DynamoDB item")]
+    MissingAttribute(&'static str),
+    /// A session's stored turn history could not be deserialized.
+    #[error("failed to deserialize stored session data: {0}")]
+    Deserialize(#[from] serde_json::Error),
+}
+
+impl WonderNavError {
+    /// Maps this error to the HTTP status code `function_handler` should report. `RateLimited`
+    /// maps to `429`, upstream provider failures to `502`, and everything else — DynamoDB errors,
+    /// malformed cache items, session deserialization failures — to `500` since they're internal
+    /// to this service rather than something the caller can retry differently.
+    fn status_code(&self) -> u16 {
+        match self {
+            WonderNavError::RateLimited => 429,
+            WonderNavError::OpenAiRequest(_) => 502,
+            WonderNavError::DynamoQuery(_)
+            | WonderNavError::MissingAttribute(_)
+            | WonderNavError::Deserialize(_) => 500,
+        }
+    }
+}
+
+/// Classifies an `async-openai` error as rate limiting vs. any other upstream failure, since the
+/// two map to different HTTP status codes in [`WonderNavError::status_code`].
+fn classify_openai_error(err: OpenAIError) -> WonderNavError {
+    if let OpenAIError::ApiError(api_err) = &err {
+        if api_err.code.as_deref() == Some("rate_limit_exceeded") {
+            return WonderNavError::RateLimited;
+        }
+    }
+    WonderNavError::OpenAiRequest(err.to_string())
+}
+
+/// Builds a non-streaming error response: a plain-text body carrying the error's `Display`
+/// output and the status code from [`WonderNavError::status_code`]. Used only for failures
+/// detected before any part of the itinerary stream has been sent — once streaming has started
+/// the response's headers (including its `200` status) are already committed, so later failures
+/// can only be logged, not reflected in the status code.
+fn error_response(err: &WonderNavError) -> Result<StreamingResponse<Body>, Error> {
+    tracing::error!("request failed: {err}");
+    Ok(StreamingResponse::builder()
+        .status(err.status_code())
+        .body(Body::from(err.to_string()))?)
+}
+
+/// Operational configuration for `function_handler` that's independent of the LLM backend: the
+/// DynamoDB table names and the AWS region to use. Loaded once at cold start (see `main`) so the
+/// same binary can run against differently-named tables per environment (e.g. staging vs.
+/// production) without a rebuild.
+#[derive(Debug, Clone)]
+struct Settings {
+    /// The DynamoDB table the itinerary cache is stored in.
+    chats_table: String,
+    /// The DynamoDB table conversation sessions are stored in.
+    sessions_table: String,
+    /// An optional AWS region override; when unset, the SDK's default region resolution is used.
+    region: Option<String>,
+}
+
+impl Settings {
+    /// Loads operational settings from environment variables.
+    ///
+    /// # Environment variables
+    /// * `WONDERNAV_CHATS_TABLE` - the itinerary cache table name (default `WonderNAV-Chats`).
+    /// * `WONDERNAV_SESSIONS_TABLE` - the session table name (default `WonderNAV-Sessions`).
+    /// * `WONDERNAV_AWS_REGION` - an optional AWS region override.
+    fn from_env() -> Self {
+        Self {
+            chats_table: std::env::var("WONDERNAV_CHATS_TABLE")
+                .unwrap_or_else(|_| "WonderNAV-Chats".to_string()),
+            sessions_table: std::env::var("WONDERNAV_SESSIONS_TABLE")
+                .unwrap_or_else(|_| "WonderNAV-Sessions".to_string()),
+            region: std::env::var("WONDERNAV_AWS_REGION").ok(),
+        }
+    }
+}
+
+/// Identifies which kind of LLM backend a `ProviderConfig` talks to.
+///
+/// `OpenAi` targets the public OpenAI API. `Custom` points at an alternate `api_base` for a
+/// self-hosted, OpenAI-API-compatible gateway. There's deliberately no Azure OpenAI variant:
+/// Azure requires an `api-key` header instead of `Authorization: Bearer` and an `api-version`
+/// query parameter, neither of which `OpenAIConfig` can express, so a variant that just threaded
+/// `api_base` through it (as `Custom` does) would silently fail to authenticate against Azure.
+#[derive(Debug, Clone, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+enum ProviderType {
+    OpenAi,
+    Custom,
+}
+
+/// Configuration for the LLM backend `open_chat_stream` talks to.
+///
+/// This is loaded once at cold start from environment variables so the crate can be pointed at
+/// a self-hosted, OpenAI-API-compatible gateway, and so the model can be rotated, without
+/// recompiling or committing a secret key.
+///
+/// # Attributes
+/// * `provider` - Which backend family to target (`openai` or `custom`).
+/// * `api_key` - The API key used to authenticate with the backend.
+/// * `api_base` - An optional override for the API base URL (required for `custom`).
+/// * `organization_id` - An optional OpenAI organization ID.
+/// * `proxy` - An optional HTTP/SOCKS5 proxy URL the outbound client should be built with.
+/// * `connect_timeout` - The connect timeout applied to the underlying `reqwest` client.
+/// * `model` - The model name passed to the chat completion request.
+/// * `system_prompt` - The travel-agent instructions sent as the `system` message, kept separate
+///   from user input so user text can't override them.
+/// * `temperature` - Sampling temperature passed to the chat completion request.
+/// * `max_tokens` - The maximum number of tokens to generate.
+/// * `image_size` - The image size requested from the image generation API (e.g. `1024x1024`).
+/// * `image_n` - The number of destination images to request when `include_image` is set.
+#[derive(Debug, Clone)]
+struct ProviderConfig {
+    provider: ProviderType,
+    api_key: String,
+    api_base: Option<String>,
+    organization_id: Option<String>,
+    proxy: Option<String>,
+    connect_timeout: Duration,
+    model: String,
+    system_prompt: String,
+    temperature: f32,
+    max_tokens: u16,
+    image_size: String,
+    image_n: u8,
+}
+
+impl ProviderConfig {
+    /// Loads the provider configuration from environment variables.
+    ///
+    /// # Environment variables
+    /// * `WONDERNAV_PROVIDER` - `openai` (default) or `custom`. Any other value is rejected
+    ///   rather than silently falling back to `openai`, since picking a provider whose auth
+    ///   scheme isn't actually implemented should fail loudly instead of connecting as the
+    ///   wrong backend.
+    /// * `WONDERNAV_API_KEY` - the API key (required).
+    /// * `WONDERNAV_API_BASE` - the API base URL (required for `custom`).
+    /// * `WONDERNAV_ORG_ID` - an optional OpenAI organization ID.
+    /// * `WONDERNAV_PROXY` - an optional proxy URL for the outbound `reqwest` client.
+    /// * `WONDERNAV_CONNECT_TIMEOUT_SECS` - connect timeout in seconds (default `10`).
+    /// * `WONDERNAV_MODEL` - the model name (default `gpt-3.5-turbo`).
+    /// * `WONDERNAV_SYSTEM_PROMPT` - the travel-agent system instructions (default
+    ///   [`TRAVEL_AGENT_PROMPT`]).
+    /// * `WONDERNAV_TEMPERATURE` - sampling temperature (default `0.7`).
+    /// * `WONDERNAV_MAX_TOKENS` - max tokens to generate (default `900`).
+    /// * `WONDERNAV_IMAGE_SIZE` - image size requested from the image generation API (default
+    ///   `512x512`).
+    /// * `WONDERNAV_IMAGE_N` - number of destination images to request (default `1`).
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if `WONDERNAV_API_KEY` is missing, if `WONDERNAV_API_BASE` is missing
+    /// while `WONDERNAV_PROVIDER` is `custom`, or if `WONDERNAV_PROVIDER` is set to something
+    /// other than `openai` or `custom`.
+    fn from_env() -> Result<Self, Box<dyn std::error::Error>> {
+        let provider = match std::env::var("WONDERNAV_PROVIDER") {
+            Err(_) => ProviderType::OpenAi,
+            Ok(raw) if raw.eq_ignore_ascii_case("openai") => ProviderType::OpenAi,
+            Ok(raw) if raw.eq_ignore_ascii_case("custom") => ProviderType::Custom,
+            Ok(raw) => {
+                return Err(format!(
+                    "unknown WONDERNAV_PROVIDER '{raw}'; expected 'openai' or 'custom'"
+                )
+                .into())
+            }
+        };
+
+        let api_key = std::env::var("WONDERNAV_API_KEY")
+            .map_err(|_| "WONDERNAV_API_KEY must be set".to_string())?;
+        let api_base = std::env::var("WONDERNAV_API_BASE").ok();
+
+        if matches!(provider, ProviderType::Custom) && api_base.is_none() {
+            return Err("WONDERNAV_API_BASE must be set for the custom provider"
+                .to_string()
+                .into());
+        }
+
+        let connect_timeout = std::env::var("WONDERNAV_CONNECT_TIMEOUT_SECS")
+            .ok()
+            .and_then(|raw| raw.parse::<u64>().ok())
+            .map(Duration::from_secs)
+            .unwrap_or(Duration::from_secs(10));
+
+        let temperature = std::env::var("WONDERNAV_TEMPERATURE")
+            .ok()
+            .and_then(|raw| raw.parse::<f32>().ok())
+            .unwrap_or(0.7);
+        let max_tokens = std::env::var("WONDERNAV_MAX_TOKENS")
+            .ok()
+            .and_then(|raw| raw.parse::<u16>().ok())
+            .unwrap_or(900);
+        let image_n = std::env::var("WONDERNAV_IMAGE_N")
+            .ok()
+            .and_then(|raw| raw.parse::<u8>().ok())
+            .unwrap_or(1);
+
+        Ok(Self {
+            provider,
+            api_key,
+            api_base,
+            organization_id: std::env::var("WONDERNAV_ORG_ID").ok(),
+            proxy: std::env::var("WONDERNAV_PROXY").ok(),
+            connect_timeout,
+            model: std::env::var("WONDERNAV_MODEL")
+                .unwrap_or_else(|_| "gpt-3.5-turbo".to_string()),
+            system_prompt: std::env::var("WONDERNAV_SYSTEM_PROMPT")
+                .unwrap_or_else(|_| TRAVEL_AGENT_PROMPT.to_string()),
+            temperature,
+            max_tokens,
+            image_size: std::env::var("WONDERNAV_IMAGE_SIZE")
+                .unwrap_or_else(|_| "512x512".to_string()),
+            image_n,
+        })
+    }
+
+    /// Builds the `async-openai` client described by this `ProviderConfig`.
+    ///
+    /// The `custom` provider gets its `api_base` threaded through `with_api_base`; an
+    /// `organization_id`, if set, is threaded through `with_org_id`. A dedicated `reqwest::Client`
+    /// is built with the configured connect timeout and handed to the OpenAI client; when `proxy`
+    /// is set, that proxy is added to it so requests are routed through it.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the proxy URL is malformed or the `reqwest` client fails to build.
+    fn build_client(self) -> Result<OpenAIClient<OpenAIConfig>, Box<dyn std::error::Error>> {
+        let mut config = OpenAIConfig::new().with_api_key(self.api_key);
+
+        if let Some(api_base) = self.api_base {
+            config = config.with_api_base(api_base);
+        }
+        if let Some(organization_id) = self.organization_id {
+            config = config.with_org_id(organization_id);
+        }
+
+        let mut http_client = reqwest::Client::builder().connect_timeout(self.connect_timeout);
+        if let Some(proxy_url) = self.proxy {
+            http_client = http_client.proxy(reqwest::Proxy::all(proxy_url)?);
+        }
+        Ok(OpenAIClient::with_config(config).with_http_client(http_client.build()?))
+    }
+}
 
 /// Represents an HTTP request.
 ///
 /// This struct is used to deserialize incoming HTTP requests.
-/// It focuses on capturing the body of the request as a `String`.
+/// It captures the body of the request and, optionally, the ID of an existing conversation
+/// session so a follow-up message (e.g. "make day 2 more relaxed") can be answered with the
+/// prior turns as context.
 ///
 /// # Attributes
 /// * `body` - A `String` containing the body of the HTTP request.
+/// * `session_id` - An optional ID identifying a prior conversation to continue. When absent, a
+///   new session is started and its ID is returned to the caller via the `x-session-id` response
+///   header.
+/// * `include_image` - When `true`, a destination image is generated (or served from cache)
+///   alongside the itinerary. Defaults to `false` so callers that only want text pay no extra
+///   latency or cost.
 #[derive(Deserialize)]
 struct Request {
     body: String,
+    #[serde(default)]
+    session_id: Option<String>,
+    #[serde(default)]
+    include_image: bool,
+}
+
+/// An itinerary served from the itinerary cache (`settings.chats_table`), with whatever
+/// destination image URLs (zero or more, per `image_n`) were previously generated for it.
+struct CachedItinerary {
+    output: String,
+    image_urls: Vec<String>,
+}
+
+/// A single turn in a conversation, either from the user or the assistant.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct Turn {
+    role: String,
+    content: String,
+}
+
+/// The conversation history associated with a session ID.
+///
+/// Sessions are stored in a separate DynamoDB table (`settings.sessions_table`) from the
+/// itinerary cache (`settings.chats_table`), since they key on `session_id` rather than on
+/// prompt content and expire on a much shorter TTL.
+#[derive(Debug, Clone, Default)]
+struct Session {
+    turns: Vec<Turn>,
 }
 
-/// Represents an HTTP response.
+/// Renders a conversation's turns as a plain-text transcript used to derive the itinerary cache
+/// key (see [`cache_key`]), so a follow-up turn in an existing session lands on a different
+/// cache entry than the same message asked as a fresh conversation. The chat completion request
+/// itself is built directly from `turns` in `open_chat_stream` rather than from this rendering.
+fn render_transcript(turns: &[Turn]) -> String {
+    let mut rendered = String::new();
+    for turn in turns {
+        if turn.role == "assistant" {
+            rendered.push_str("Assistant: ");
+        } else {
+            rendered.push_str("User: ");
+        }
+        rendered.push_str(&turn.content);
+        rendered.push('\n');
+    }
+    rendered
+}
+
+/// Loads the conversation history for a session from DynamoDB.
 ///
-/// This struct is used to serialize data into an HTTP response format.
-/// It includes both a status code and a response body.
+/// Mirrors `query_dynamodb`: looks up `settings.sessions_table` by `session_id` and treats a
+/// session whose `ttl` has passed as if it didn't exist, so a stale session starts fresh instead
+/// of resuming with history the user wouldn't expect.
 ///
-/// Note: The `statusCode` field uses camelCase as specified by AWS standards.
+/// # Errors
 ///
-/// # Attributes
-/// * `statusCode` - An `i32` representing the HTTP status code of the response.
-/// * `body` - A `String` containing the body of the HTTP response.
-#[derive(Serialize)]
-struct Response {
-    statusCode: i32, // AWS specifies for me to use camelCase for statusCode here
-    body: String,
+/// Returns [`WonderNavError::DynamoQuery`] if the DynamoDB query fails, or
+/// [`WonderNavError::Deserialize`] if the stored `turns` attribute exists but isn't valid JSON.
+async fn load_session(
+    client: &Client,
+    settings: &Settings,
+    session_id: &str,
+) -> Result<Session, WonderNavError> {
+    let resp = client
+        .get_item()
+        .table_name(&settings.sessions_table)
+        .key("session_id", AttributeValue::S(session_id.to_string()))
+        .send()
+        .await
+        .map_err(DynamoError::from)?;
+
+    let Some(item) = resp.item else {
+        return Ok(Session::default());
+    };
+
+    let expired = item
+        .get("ttl")
+        .and_then(|attr| attr.as_n().ok())
+        .and_then(|raw| raw.parse::<u64>().ok())
+        .map(|ttl| ttl <= now_epoch_secs())
+        .unwrap_or(false);
+    if expired {
+        return Ok(Session::default());
+    }
+
+    let turns = match item.get("turns").and_then(|attr| attr.as_s().ok()) {
+        Some(raw) => serde_json::from_str::<Vec<Turn>>(raw)?,
+        None => Vec::new(),
+    };
+
+    Ok(Session { turns })
+}
+
+/// Persists the conversation history for a session to DynamoDB, refreshing its TTL.
+///
+/// Mirrors the `put_item` call in `function_handler`: serializes `session.turns` to JSON and
+/// writes it to `settings.sessions_table` alongside a fresh `ttl` so idle sessions expire after
+/// [`SESSION_TTL_DAYS`].
+///
+/// # Errors
+///
+/// Returns [`WonderNavError::DynamoQuery`] if the DynamoDB write fails.
+async fn save_session(
+    client: &Client,
+    settings: &Settings,
+    session_id: &str,
+    session: &Session,
+) -> Result<(), WonderNavError> {
+    let turns_json = serde_json::to_string(&session.turns).unwrap_or_default();
+    let ttl = now_epoch_secs() + SESSION_TTL_DAYS * 24 * 60 * 60;
+
+    client
+        .put_item()
+        .table_name(&settings.sessions_table)
+        .item("session_id", AttributeValue::S(session_id.to_string()))
+        .item("turns", AttributeValue::S(turns_json))
+        .item("ttl", AttributeValue::N(ttl.to_string()))
+        .send()
+        .await
+        .map_err(DynamoError::from)?;
+
+    Ok(())
 }
 
 /// Asynchronous AWS Lambda function handler for processing requests.
 ///
 /// This function serves as an AWS Lambda handler. It takes an `event` of type `LambdaEvent<Request>`
-/// and processes it to generate a response. The function queries a DynamoDB table with the request body.
-/// If a matching record is found, it returns the record's data. If not, it generates a new response
-/// using an external API, stores the result in DynamoDB, and then returns it.
+/// and processes it to generate a response. It loads the session's prior turns (if any), appends
+/// the new message, and renders the whole conversation into a transcript, which is what's
+/// actually looked up in and written to the itinerary cache — so a follow-up in an existing
+/// session is cached separately from the same text asked as a fresh conversation. If a matching
+/// cached transcript is found, its contents are written to the response body in a single chunk.
+/// If not, an OpenAI chat completion stream is opened and, once that succeeds, the itinerary is
+/// forwarded to the client as the tokens arrive, using Lambda response streaming so the caller
+/// sees output long before the full itinerary is complete. Once the stream ends, the accumulated
+/// text is persisted to both the itinerary cache and the session's turn history. When
+/// `include_image` is set on the request, the configured number of destination images
+/// (`image_n`) are generated (or served from the same cache item) and each URL is appended to
+/// the body as its own clearly delimited chunk, since the response is a single streaming body
+/// rather than a structured JSON object with separate fields.
+///
+/// Failures detected before the response body is opened — a misconfigured provider, a failed
+/// session load or cache lookup, or a failure to open the completion stream — are reported as a
+/// non-streaming error response with a status code from [`WonderNavError::status_code`] rather
+/// than the `200` the streaming path always returns. Once streaming has started, the `200` status
+/// is already committed, so failures at that point (a mid-flight stream error, a failed cache or
+/// session write) can only be logged.
 ///
 /// # Arguments
 ///
 /// * `event` - A `LambdaEvent<Request>` object representing the AWS Lambda event. It contains
 ///   the request payload.
+/// * `settings` - Operational settings (table names, region), loaded once at cold start.
+/// * `provider_config` - The LLM backend configuration, loaded once at cold start.
 ///
 /// # Returns
 ///
-/// Returns a `Result<Response, Error>`. On successful processing of the event, it returns `Ok(Response)`
-/// where `Response` contains the HTTP status code and the body of the response. In the event of an error
-/// (such as a failure to query DynamoDB), it returns `Ok(Response)` with a status code of 500 and an error message.
-///
-/// # Errors
-///
-/// Errors can arise from:
-///
-/// - Failures in querying DynamoDB.
-/// - Failures in generating a response using the external API.
-/// - Failures in putting a new item into the DynamoDB table.
-///
-/// In case of any error, the function returns a `Response` with a status code of 500 and an error message.
-async fn function_handler(event: LambdaEvent<Request>) -> Result<Response, Error> {
+/// Returns a `Result<StreamingResponse<Body>, Error>`. On the happy path the response is a streaming
+/// HTTP body: the caller receives itinerary text incrementally rather than waiting for the
+/// entire response. The session ID — freshly minted if the request didn't supply one — is
+/// returned via the `x-session-id` header so the client can continue the conversation in a later
+/// request.
+async fn function_handler(
+    event: LambdaEvent<Request>,
+    settings: Settings,
+    provider_config: ProviderConfig,
+) -> Result<StreamingResponse<Body>, Error> {
     let request = event.payload;
 
-    // Initialize DynamoDB client
-    let config = aws_config::load_defaults(BehaviorVersion::latest()).await;
-    let client = Client::new(&config);
-
-    // Query DynamoDB
-    match query_dynamodb(&client, &request.body).await {
-        Ok(Some(res)) => Ok(Response {
-            statusCode: 200,
-            body: res.to_string(),
-        }),
-        Ok(None) => {
-            let openai_resp = transform_result(generate_response(&request.body).await);
-            let _put_response = client
-                .put_item()
-                .table_name("WonderNAV-Chats")
-                .item("input", AttributeValue::S(request.body.clone()))
-                .item("output", AttributeValue::S(openai_resp.clone()))
-                .send()
-                .await?;
-            Ok(Response {
-                statusCode: 200,
-                body: openai_resp,
-            })
+    let mut aws_config_loader = aws_config::defaults(BehaviorVersion::latest());
+    if let Some(region) = &settings.region {
+        aws_config_loader = aws_config_loader.region(aws_config::Region::new(region.clone()));
+    }
+    let client = Client::new(&aws_config_loader.load().await);
+
+    let include_image = request.include_image;
+    let destination = request.body.clone();
+
+    let session_id = request
+        .session_id
+        .clone()
+        .unwrap_or_else(|| Uuid::new_v4().to_string());
+    let mut session = match load_session(&client, &settings, &session_id).await {
+        Ok(session) => session,
+        Err(e) => return error_response(&e),
+    };
+    session.turns.push(Turn {
+        role: "user".to_string(),
+        content: sanitize_user_input(&request.body),
+    });
+
+    let transcript = render_transcript(&session.turns);
+    let key = cache_key(&transcript, &provider_config.model, &provider_config.system_prompt);
+
+    let cached = match query_dynamodb(&client, &settings, &key).await {
+        Ok(cached) => cached,
+        Err(e) => return error_response(&e),
+    };
+
+    match cached {
+        Some(cached) => {
+            session.turns.push(Turn {
+                role: "assistant".to_string(),
+                content: cached.output.clone(),
+            });
+            if let Err(e) = save_session(&client, &settings, &session_id, &session).await {
+                return error_response(&e);
+            }
+
+            let (mut tx, rx) = Body::channel();
+            let image_provider_config = provider_config.clone();
+            tokio::spawn(async move {
+                let _ = tx.send_data(cached.output.clone().into()).await;
+
+                if !include_image {
+                    return;
+                }
+
+                let newly_generated = cached.image_urls.is_empty();
+                let image_urls = if newly_generated {
+                    generate_image(&destination, image_provider_config)
+                        .await
+                        .unwrap_or_default()
+                } else {
+                    cached.image_urls
+                };
+
+                for url in &image_urls {
+                    let _ = tx
+                        .send_data(format!("\n\n[destination-image]: {url}\n").into())
+                        .await;
+                }
+
+                // Only persist when this request actually generated new URLs — a pure cache
+                // read (the `else` branch above) must not re-write the item or refresh its
+                // `ttl`, or a popular itinerary would never expire while being viewed.
+                if newly_generated && !image_urls.is_empty() {
+                    let ttl = now_epoch_secs() + CACHE_TTL_DAYS * 24 * 60 * 60;
+                    let _ = client
+                        .put_item()
+                        .table_name(&settings.chats_table)
+                        .item("input", AttributeValue::S(key.clone()))
+                        .item("output", AttributeValue::S(cached.output))
+                        .item("ttl", AttributeValue::N(ttl.to_string()))
+                        .item(
+                            "image_urls",
+                            AttributeValue::S(serde_json::to_string(&image_urls).unwrap_or_default()),
+                        )
+                        .send()
+                        .await;
+                }
+            });
+
+            Ok(StreamingResponse::builder()
+                .status(200)
+                .header("x-session-id", session_id)
+                .body(rx)?)
+        }
+        None => {
+            let turns_snapshot = session.turns.clone();
+            let stream = match open_chat_stream(&turns_snapshot, &provider_config).await {
+                Ok(stream) => stream,
+                Err(e) => return error_response(&e),
+            };
+
+            let (mut tx, rx) = Body::channel();
+            tokio::spawn(async move {
+                let (delta_tx, mut delta_rx) = mpsc::unbounded_channel::<String>();
+                let generation = tokio::spawn(forward_stream(stream, delta_tx));
+
+                while let Some(delta) = delta_rx.recv().await {
+                    if tx.send_data(delta.into()).await.is_err() {
+                        // Client disconnected; stop forwarding but let generation finish so
+                        // we can still decide whether to cache the accumulated text below.
+                        break;
+                    }
+                }
+
+                match generation.await {
+                    Ok(accumulated) if !accumulated.is_empty() => {
+                        let image_urls = if include_image {
+                            generate_image(&destination, provider_config)
+                                .await
+                                .unwrap_or_default()
+                        } else {
+                            Vec::new()
+                        };
+
+                        for url in &image_urls {
+                            let _ = tx
+                                .send_data(format!("\n\n[destination-image]: {url}\n").into())
+                                .await;
+                        }
+
+                        let ttl = now_epoch_secs() + CACHE_TTL_DAYS * 24 * 60 * 60;
+                        let mut put = client
+                            .put_item()
+                            .table_name(&settings.chats_table)
+                            .item("input", AttributeValue::S(key.clone()))
+                            .item("output", AttributeValue::S(accumulated.clone()))
+                            .item("ttl", AttributeValue::N(ttl.to_string()));
+                        if !image_urls.is_empty() {
+                            put = put.item(
+                                "image_urls",
+                                AttributeValue::S(serde_json::to_string(&image_urls).unwrap_or_default()),
+                            );
+                        }
+                        let _put_response = put.send().await;
+
+                        session.turns.push(Turn {
+                            role: "assistant".to_string(),
+                            content: accumulated,
+                        });
+                        let _ = save_session(&client, &settings, &session_id, &session).await;
+                    }
+                    Ok(_) => {
+                        // Nothing was accumulated (e.g. the stream errored before any delta
+                        // arrived); skip the cache write and leave the session as it was before
+                        // this turn rather than persisting a turn with no reply.
+                    }
+                    Err(e) => {
+                        tracing::error!("generation task panicked: {e}");
+                    }
+                }
+            });
+
+            Ok(StreamingResponse::builder()
+                .status(200)
+                .header("x-session-id", session_id)
+                .body(rx)?)
         }
-        Err(e) => Ok(Response {
-            statusCode: 500,
-            body: format!("Error querying DynamoDB: {}", e),
-        }),
     }
 }
 
-/// Transforms a `Result` into a `String`, providing a default error message on failure.
+/// Opens a streamed OpenAI chat completion for the conversation so far, without consuming it.
 ///
-/// This function is designed to handle the output from functions that return a `Result<String, Box<dyn std::error::Error>>`.
-/// It simplifies the error handling by converting any `Err` variant into a generic error message string.
+/// The travel-agent instructions are sent as a dedicated `system` message (from
+/// `provider_config.system_prompt`) and each prior turn is sent as its own `user` or `assistant`
+/// message, so user-supplied text can never be interpreted as an instruction override the way it
+/// could when the whole prompt was one concatenated string.
 ///
-/// # Arguments
+/// This is deliberately split from [`forward_stream`]: opening the stream is the last point at
+/// which a request can fail before `function_handler` commits to a `200` streaming response, so
+/// `function_handler` awaits this first and maps a failure to a real status code (`429` for rate
+/// limiting, `502` for any other upstream failure) before opening the Lambda response body.
 ///
-/// * `result` - A `Result` object which may contain either a `String` or a `Box<dyn std::error::Error>`.
+/// The request always asks for a single completion choice: `forward_stream` only ever reads
+/// `choices.first()`, so requesting more would just mean paying for and discarding completions
+/// nothing downstream consumes.
 ///
-/// # Returns
+/// # Errors
 ///
-/// Returns a `String`. If `result` is `Ok`, it returns the contained `String`. If `result` is `Err`, it returns
-/// a default error message: `"Error generating response."`.
-fn transform_result(result: Result<String, Box<dyn std::error::Error>>) -> String {
-    match result {
-        Ok(str_ref) => str_ref.to_string(),
-        Err(_) => "Error generating response.".to_string(),
+/// Returns [`WonderNavError::RateLimited`] if the provider reports its rate limit was exceeded,
+/// or [`WonderNavError::OpenAiRequest`] for any other failure to build or open the request
+/// (network issues, an invalid API key, a malformed request).
+async fn open_chat_stream(
+    turns: &[Turn],
+    provider_config: &ProviderConfig,
+) -> Result<BoxStream<'static, Result<CreateChatCompletionStreamResponse, OpenAIError>>, WonderNavError>
+{
+    let openai_client = provider_config
+        .clone()
+        .build_client()
+        .map_err(|e| WonderNavError::OpenAiRequest(e.to_string()))?;
+
+    let mut messages = vec![ChatCompletionRequestSystemMessageArgs::default()
+        .content(provider_config.system_prompt.clone())
+        .build()
+        .map_err(|e| WonderNavError::OpenAiRequest(e.to_string()))?
+        .into()];
+    for turn in turns {
+        let message = if turn.role == "assistant" {
+            ChatCompletionRequestAssistantMessageArgs::default()
+                .content(turn.content.clone())
+                .build()
+                .map_err(|e| WonderNavError::OpenAiRequest(e.to_string()))?
+                .into()
+        } else {
+            ChatCompletionRequestUserMessageArgs::default()
+                .content(turn.content.clone())
+                .build()
+                .map_err(|e| WonderNavError::OpenAiRequest(e.to_string()))?
+                .into()
+        };
+        messages.push(message);
     }
+
+    let openai_request = CreateChatCompletionRequestArgs::default()
+        .model(provider_config.model.clone())
+        .messages(messages)
+        .temperature(provider_config.temperature)
+        .max_tokens(provider_config.max_tokens)
+        .build()
+        .map_err(|e| WonderNavError::OpenAiRequest(e.to_string()))?;
+
+    let stream = openai_client
+        .chat()
+        .create_stream(openai_request)
+        .await
+        .map_err(classify_openai_error)?;
+
+    Ok(Box::pin(stream))
 }
 
-/// Generates a travel itinerary based on the provided input using the OpenAI API.
-///
-/// This asynchronous function sends a request to the OpenAI API to generate a detailed travel itinerary.
-/// The request includes a predefined prompt to which the user's input is appended. The function
-/// then parses the response to extract the generated itinerary.
+/// Consumes an already-open chat completion stream (see [`open_chat_stream`]), forwarding each
+/// incremental content delta to `delta_tx` as it arrives so the caller can relay partial output
+/// to the client without waiting for the full itinerary. Returns whatever text was accumulated
+/// once the stream is exhausted (the OpenAI client already terminates the stream on the `[DONE]`
+/// sentinel and reassembles multi-byte UTF-8 across chunk boundaries), even if the stream
+/// terminated early due to a mid-flight error — callers should treat an empty accumulated string
+/// as "nothing worth caching" rather than as an error. By this point the response's status code
+/// is already committed, so a mid-flight error can only be logged, not reflected in the status.
+async fn forward_stream(
+    mut stream: BoxStream<'static, Result<CreateChatCompletionStreamResponse, OpenAIError>>,
+    delta_tx: mpsc::UnboundedSender<String>,
+) -> String {
+    let mut accumulated = String::new();
+    while let Some(next) = stream.next().await {
+        match next {
+            Ok(response) => {
+                if let Some(choice) = response.choices.first() {
+                    if let Some(delta) = &choice.delta.content {
+                        if !delta.is_empty() {
+                            accumulated.push_str(delta);
+                            // The receiver may have gone away if the client disconnected; that's
+                            // not a reason to abort accumulation, since we still want to cache it.
+                            let _ = delta_tx.send(delta.clone());
+                        }
+                    }
+                }
+            }
+            Err(e) => {
+                tracing::error!("stream error mid-flight: {e}");
+                break;
+            }
+        }
+    }
+
+    accumulated
+}
+
+/// Generates one or more destination images via the OpenAI image generation API, parallel to
+/// `open_chat_stream`/`forward_stream` but for imagery rather than itinerary text.
 ///
 /// # Arguments
 ///
-/// * `input` - A string slice that represents the user's input. This should typically include
-///   the location and duration of the intended trip.
+/// * `destination` - The raw user input (location and duration), used to derive the image prompt.
+/// * `provider_config` - The LLM backend and image generation parameters (`image_size`,
+///   `image_n`) to use.
 ///
 /// # Returns
 ///
-/// This function returns a `Result` type. On success, it returns `Ok(String)` containing
-/// the generated itinerary. In the event of an error (such as a problem with the API request),
-/// it returns an `Err` with a boxed `dyn std::error::Error`.
+/// Returns the generated image URLs on success, in the order returned by the API.
 ///
 /// # Errors
 ///
-/// This function will return an error in several cases, including:
-///
-/// - Problems with the network connectivity.
-/// - Errors from the OpenAI API (e.g., invalid API key, API limitations).
-/// - Issues with building the OpenAI API request.
-async fn generate_response(input: &str) -> Result<String, Box<dyn std::error::Error>> {
-    let api_key = "api key placeholder"; //todo: api key here
-    let openai_config = OpenAIConfig::new().with_api_key(api_key);
-
-    let openai_client = OpenAIClient::with_config(openai_config);
-    let mut openai_prompt = "You are an experienced travel agent that will provide an in-depth itinerary based on relevant online articles. You will provide the itinerary based on the location and duration entered by the user. Include at least 3 activities a day. Do not include any other suggestions or comments before or after the itinerary."
-        .to_string();
-    openai_prompt.push_str(input);
+/// This function will return an error if the request fails to build or the OpenAI API call
+/// fails (e.g. invalid API key, rate limiting).
+async fn generate_image(
+    destination: &str,
+    provider_config: ProviderConfig,
+) -> Result<Vec<String>, Box<dyn std::error::Error>> {
+    let image_size = parse_image_size(&provider_config.image_size);
+    let image_n = provider_config.image_n;
+    let openai_client = provider_config.build_client()?;
 
-    let openai_request = CreateCompletionRequestArgs::default()
-        .model("text-davinci-003")
-        .prompt(openai_prompt)
-        .max_tokens(900_u16)
+    let request = CreateImageRequestArgs::default()
+        .prompt(format!(
+            "A scenic, photorealistic travel photo of {destination}"
+        ))
+        .n(image_n)
+        .size(image_size)
         .build()
         .map_err(|e| Box::new(e) as Box<dyn std::error::Error>)?;
 
-    let openai_response = openai_client
-        .completions()
-        .create(openai_request)
+    let response = openai_client
+        .images()
+        .create(request)
         .await
         .map_err(|e| Box::new(e) as Box<dyn std::error::Error>)?;
 
-    let output = &openai_response.choices[0].text;
-    Ok(output.to_string())
+    Ok(response
+        .data
+        .iter()
+        .filter_map(|image| match image.as_ref() {
+            Image::Url { url, .. } => Some(url.clone()),
+            Image::B64Json { .. } => None,
+        })
+        .collect())
 }
 
 /// Queries a DynamoDB table for a specific item.
 ///
-/// This asynchronous function takes a reference to a DynamoDB `Client` and a string `input`.
-/// It queries the DynamoDB table "WonderNAV-Chats" for an item with a key matching the `input`.
+/// This asynchronous function takes a reference to a DynamoDB `Client` and a `key` (see
+/// [`cache_key`]). It queries `settings.chats_table` for an item with a matching key. An item
+/// whose `ttl` attribute has already passed is treated as a cache miss even if DynamoDB hasn't
+/// yet reaped it, so the caller regenerates the itinerary instead of serving stale text.
 ///
 /// # Arguments
 ///
 /// * `client` - A reference to the DynamoDB `Client` used to perform the query.
-/// * `input` - A string slice that represents the key of the item to query in the DynamoDB table.
+/// * `settings` - Operational settings; used for the cache table name.
+/// * `key` - The cache key to look up, as produced by [`cache_key`].
 ///
 /// # Returns
 ///
-/// This function returns a `Result` type. On success, it returns `Ok(Some(String))` where the `String`
-/// is the value corresponding to the 'output' attribute of the item found in the table.
-/// If the 'output' attribute is not found or the item does not exist in the table,
-/// it returns `Ok(None)`.
-///
-/// In the case of an error during the query (e.g., network issues, permissions problems),
-/// it returns an `Err(DynamoError)`.
+/// This function returns a `Result` type. On success, it returns `Ok(Some(CachedItinerary))`
+/// with the cached itinerary text and whatever destination image URLs were generated for this
+/// item. If the item does not exist or its `ttl` has passed, it returns `Ok(None)`.
 ///
 /// # Errors
 ///
-/// This function will return an error if the DynamoDB query fails for reasons such as
-/// network issues, incorrect permissions, or invalid input format.
-async fn query_dynamodb(client: &Client, input: &str) -> Result<Option<String>, DynamoError> {
+/// Returns [`WonderNavError::DynamoQuery`] if the DynamoDB query fails. Returns
+/// [`WonderNavError::MissingAttribute`] if the item exists, is unexpired, but its `output`
+/// attribute is absent or isn't a string — that's a malformed item, not a cache miss, and
+/// shouldn't be papered over with a placeholder string standing in for real itinerary text.
+async fn query_dynamodb(
+    client: &Client,
+    settings: &Settings,
+    key: &str,
+) -> Result<Option<CachedItinerary>, WonderNavError> {
     let resp = client
         .get_item()
-        .table_name("WonderNAV-Chats")
-        .key("input", AttributeValue::S(input.to_string()))
+        .table_name(&settings.chats_table)
+        .key("input", AttributeValue::S(key.to_string()))
         .send()
-        .await?;
+        .await
+        .map_err(DynamoError::from)?;
 
-    return if let Some(item) = resp.item {
-        if let Some(output_attr) = item.get("output") {
-            match output_attr.as_s() {
-                Ok(output) => Ok(Some(output.to_string())),
-                Err(_) => Ok(Some("Item attribute does not exist".to_string())),
-            }
-        } else {
-            Ok(None)
+    let Some(item) = resp.item else {
+        return Ok(None);
+    };
+
+    if let Some(ttl_attr) = item.get("ttl") {
+        let expired = ttl_attr
+            .as_n()
+            .ok()
+            .and_then(|raw| raw.parse::<u64>().ok())
+            .map(|ttl| ttl <= now_epoch_secs())
+            .unwrap_or(false);
+        if expired {
+            return Ok(None);
         }
-    } else {
-        Ok(None)
+    }
+
+    let Some(output_attr) = item.get("output") else {
+        return Err(WonderNavError::MissingAttribute("output"));
     };
+
+    let output = output_attr
+        .as_s()
+        .map_err(|_| WonderNavError::MissingAttribute("output"))?
+        .to_string();
+    let image_urls = item
+        .get("image_urls")
+        .and_then(|attr| attr.as_s().ok())
+        .and_then(|raw| serde_json::from_str::<Vec<String>>(raw).ok())
+        .unwrap_or_default();
+
+    Ok(Some(CachedItinerary { output, image_urls }))
 }
 
 /// The entry point of the application.
 ///
-/// This asynchronous function sets up the logging infrastructure and then runs the service.
-/// It uses Tokio's asynchronous runtime to drive the application and `tracing_subscriber`
-/// to set up logging. The `function_handler` is passed to the service runner.
+/// This asynchronous function sets up the logging infrastructure, loads [`Settings`] and
+/// [`ProviderConfig`] once from the environment, and then runs the service. Loading both here
+/// rather than per-invocation means a misconfigured environment fails fast at cold start instead
+/// of on the first request, and avoids re-reading and re-parsing the same environment variables
+/// on every invocation of a warm container.
 ///
 /// # Returns
 ///
@@ -241,5 +955,63 @@ async fn main() -> Result<(), Error> {
         .without_time()
         .init();
 
-    run(service_fn(function_handler)).await
+    let settings = Settings::from_env();
+    let provider_config = ProviderConfig::from_env()?;
+
+    run(service_fn(move |event: LambdaEvent<Request>| {
+        let settings = settings.clone();
+        let provider_config = provider_config.clone();
+        async move { function_handler(event, settings, provider_config).await }
+    }))
+    .await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn cache_key_changes_with_model_and_system_prompt() {
+        let base = cache_key("Paris, 3 days", "gpt-3.5-turbo", "system a");
+        assert_ne!(base, cache_key("Paris, 3 days", "gpt-4", "system a"));
+        assert_ne!(base, cache_key("Paris, 3 days", "gpt-3.5-turbo", "system b"));
+        assert_eq!(base, cache_key("Paris, 3 days", "gpt-3.5-turbo", "system a"));
+    }
+
+    #[test]
+    fn prompt_version_is_stable_and_sensitive_to_changes() {
+        assert_eq!(prompt_version("same prompt"), prompt_version("same prompt"));
+        assert_ne!(prompt_version("prompt one"), prompt_version("prompt two"));
+    }
+
+    #[test]
+    fn sanitize_user_input_strips_control_characters_but_keeps_newlines() {
+        assert_eq!(
+            sanitize_user_input("Tokyo\u{0007}, 5 days\n"),
+            "Tokyo, 5 days"
+        );
+        assert_eq!(sanitize_user_input("  Rome, 2 days  "), "Rome, 2 days");
+        assert_eq!(sanitize_user_input("Day 1\nDay 2"), "Day 1\nDay 2");
+    }
+
+    #[test]
+    fn parse_image_size_maps_known_sizes_and_falls_back() {
+        assert_eq!(parse_image_size("256x256"), ImageSize::S256x256);
+        assert_eq!(parse_image_size("1024x1024"), ImageSize::S1024x1024);
+        assert_eq!(parse_image_size("512x512"), ImageSize::S512x512);
+        assert_eq!(parse_image_size("not-a-size"), ImageSize::S512x512);
+    }
+
+    #[test]
+    fn wondernav_error_status_codes() {
+        assert_eq!(WonderNavError::RateLimited.status_code(), 429);
+        assert_eq!(
+            WonderNavError::OpenAiRequest("boom".to_string()).status_code(),
+            502
+        );
+        assert_eq!(
+            WonderNavError::MissingAttribute("output").status_code(),
+            500
+        );
+    }
 }